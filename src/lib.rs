@@ -1,41 +1,32 @@
 use std::fs::File;
-use std::os::fd::AsRawFd;
-use std::io::{Error, Result};
-
-// https://arm64.syscall.sh/
-// fn sys_flock(fd: i32, operation: i32) -> i32 {
-//     let res: i32;
-//     unsafe {
-//         asm!(
-//             "svc 0",
-//             in("x8") 0x20,
-//             in("x0") fd,
-//             in("x1") operation,
-//             lateout("x0") res,
-//             clobber_abi("C"),
-//         );
-//     }
-// 	res
-// }
-
-extern "C" {
-    fn flock(fd: i32, operation: i32) -> i32;
-}
-
-const LOCK_SH : i32 = 1;
-const LOCK_EX : i32 = 2;
-const LOCK_NB : i32 = 4;
-const LOCK_UN : i32 = 8;
+#[cfg(unix)]
+use std::os::fd::AsFd;
+#[cfg(windows)]
+use std::os::windows::io::{AsHandle, AsRawHandle};
+#[cfg(windows)]
+use std::io::Error;
+use std::io::{Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
+#[cfg(unix)]
+use rustix::fs::FlockOperation;
+
+#[cfg(windows)]
+const LOCKFILE_FAIL_IMMEDIATELY: u32 = 0x00000001;
+#[cfg(windows)]
+const LOCKFILE_EXCLUSIVE_LOCK: u32 = 0x00000002;
+
+#[derive(Clone, Copy)]
 pub enum LockType {
 	Exclusive,
 	Shared,
 }
+#[cfg(windows)]
 impl LockType {
-	fn to_flock_flag(&self) -> i32 {
+	fn to_win32_flag(&self) -> u32 {
 		match self {
-			LockType::Exclusive => LOCK_EX,
-			LockType::Shared => LOCK_SH,
+			LockType::Exclusive => LOCKFILE_EXCLUSIVE_LOCK,
+			LockType::Shared => 0,
 		}
 	}
 }
@@ -44,63 +35,269 @@ pub enum BlockMode {
 	Blocking,
 	NonBlocking,
 }
+#[cfg(windows)]
 impl BlockMode {
-	fn to_flock_flag(&self) -> i32 {
+	fn to_win32_flag(&self) -> u32 {
 		match self {
 			BlockMode::Blocking => 0,
-			BlockMode::NonBlocking => LOCK_NB,
+			BlockMode::NonBlocking => LOCKFILE_FAIL_IMMEDIATELY,
 		}
 	}
 }
 
-fn flogic(file: &File, flags: i32) -> Result<()> {
-	#[cfg(unix)]
-	// https://linux.die.net/man/2/flock
-	let inner = move || {
-		let ret = unsafe { flock(file.as_raw_fd(), flags) };
-		if ret < 0 { Err(Error::last_os_error()) } else { Ok(()) }
-	};
+#[cfg(unix)]
+/// Map this crate's platform-agnostic `LockType`/`BlockMode` onto rustix's `FlockOperation`.
+fn to_flock_operation(lock_type: &LockType, block_mode: &BlockMode) -> FlockOperation {
+	use FlockOperation::*;
+	match (lock_type, block_mode) {
+		(LockType::Exclusive, BlockMode::Blocking) => LockExclusive,
+		(LockType::Exclusive, BlockMode::NonBlocking) => NonBlockingLockExclusive,
+		(LockType::Shared, BlockMode::Blocking) => LockShared,
+		(LockType::Shared, BlockMode::NonBlocking) => NonBlockingLockShared,
+	}
+}
 
-	#[cfg(windows)]
-	// https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-lockfileex
-	let inner = move || {
-		todo!();
-		unsafe {
-			let mut overlapped = std::mem::zeroed();
-			let ret = winapi::um::fileapi::LockFileEx(file.as_raw_handle(), flags, 0, !0, !0, &mut overlapped);
-			if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) }
-		}
-	};
+#[cfg(target_os = "linux")]
+// https://linux.die.net/man/2/flock
+fn flogic(fd: impl AsFd, op: FlockOperation) -> Result<()> {
+	rustix::fs::flock(fd, op).map_err(Into::into)
+}
 
-	inner()
+// `flock` doesn't behave identically across every Unix (and doesn't work at all over
+// some network filesystems), so off Linux we fall back to POSIX `fcntl` record locks,
+// which is also what rustc does. This locks the whole file (`l_whence = SEEK_SET`,
+// `l_start = 0`, `l_len = 0`) via `F_SETLK`/`F_SETLKW`.
+//
+// This is a real semantic divergence from `flock`, not just an implementation detail:
+// `fcntl` locks are owned per-process rather than per-open-file-description, and are
+// dropped when *any* file descriptor referring to the file is closed, even one that
+// never took the lock. Two descriptors opened by the *same* process never conflict
+// with each other under this backend, unlike under `flock`.
+#[cfg(all(unix, not(target_os = "linux")))]
+// https://man7.org/linux/man-pages/man2/fcntl.2.html
+fn flogic(fd: impl AsFd, op: FlockOperation) -> Result<()> {
+	rustix::fs::fcntl_lock(fd, op).map_err(Into::into)
 }
 
-/// Place a lock advisory on this file.
-/// 
+#[cfg(windows)]
+// https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-lockfileex
+fn flogic_lock(handle: impl AsHandle, flags: u32) -> Result<()> {
+	unsafe {
+		let mut overlapped = std::mem::zeroed();
+		let ret = winapi::um::fileapi::LockFileEx(handle.as_handle().as_raw_handle() as _, flags, 0, !0, !0, &mut overlapped);
+		if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) }
+	}
+}
+
+#[cfg(windows)]
+// https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-unlockfileex
+fn flogic_unlock(handle: impl AsHandle) -> Result<()> {
+	unsafe {
+		let mut overlapped = std::mem::zeroed();
+		let ret = winapi::um::fileapi::UnlockFileEx(handle.as_handle().as_raw_handle() as _, 0, !0, !0, &mut overlapped);
+		if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) }
+	}
+}
+
+/// Place a lock advisory on this file descriptor/handle.
+///
+/// Accepts anything that implements the I/O-safety traits (`AsFd` on Unix,
+/// `AsHandle` on Windows), not just `&File` — borrowed descriptors for
+/// sockets, pipes, `OwnedFd`, etc. work too.
+///
 /// UNIX:
-/// - Uses the `flock` syscall.
-/// * `file` - A raw file descriptor will be extracted and passed to the flock syscall.
-/// 
+/// - On Linux, uses the `flock` syscall via `rustix`.
+/// - On other Unix targets, falls back to POSIX `fcntl` record locks (`F_SETLK`/
+///   `F_SETLKW`), which are owned per-process rather than per-open-file-description —
+///   see the comment on the `fcntl` backend for the semantic differences.
+///
 /// Windows:
 /// - Uses the `LockFileEx` syscall (fileapi.h).
-/// * `file` - A raw file handle will be extracted and passed to the LockFileEx syscall.
-/// 
-pub fn lock_file(file: &File, lock_type: LockType, block_mode: BlockMode) -> Result<()> {
-	flogic(file, lock_type.to_flock_flag() | block_mode.to_flock_flag())
+///
+#[cfg(unix)]
+pub fn lock_file(fd: impl AsFd, lock_type: LockType, block_mode: BlockMode) -> Result<()> {
+	flogic(fd, to_flock_operation(&lock_type, &block_mode))
+}
+#[cfg(windows)]
+pub fn lock_file(handle: impl AsHandle, lock_type: LockType, block_mode: BlockMode) -> Result<()> {
+	flogic_lock(handle, lock_type.to_win32_flag() | block_mode.to_win32_flag())
 }
 
 /// Remove a file lock advisory held by this process.
-/// 
+///
 /// UNIX:
-/// - Uses the `flock` syscall.
-/// * `file` - A raw file descriptor will be extracted and passed to the flock syscall.
-/// 
+/// - On Linux, uses the `flock` syscall via `rustix`.
+/// - On other Unix targets, falls back to POSIX `fcntl` record locks (`F_SETLKW` with `F_UNLCK`), with
+///   the same per-process (rather than per-open-file-description) semantics as
+///   [`lock_file`]'s `fcntl` backend.
+///
 /// Windows:
 /// - Uses the `UnlockFileEx` syscall (fileapi.h).
-/// * `file` - A raw file handle will be extracted and passed to the UnlockFileEx syscall.
-/// 
-pub fn unlock_file(file: &File) -> Result<()> {
-	flogic(file, LOCK_UN)
+///
+#[cfg(unix)]
+pub fn unlock_file(fd: impl AsFd) -> Result<()> {
+	flogic(fd, FlockOperation::Unlock)
+}
+#[cfg(windows)]
+pub fn unlock_file(handle: impl AsHandle) -> Result<()> {
+	flogic_unlock(handle)
+}
+
+/// Like [`lock_file`], but if the lock is already held elsewhere, `on_contended` is
+/// invoked once before blocking — e.g. to print a "Blocking waiting for file lock…"
+/// message, the way cargo does. This is done by first attempting a non-blocking
+/// lock, so the uncontended fast path takes no detour and performs no allocation.
+#[cfg(unix)]
+pub fn lock_file_with_notify(fd: impl AsFd, lock_type: LockType, on_contended: impl FnOnce()) -> Result<()> {
+	// `flock` reports contention as EWOULDBLOCK, but POSIX allows the `fcntl`
+	// fallback's F_SETLK to report it as EACCES instead, so both must be treated
+	// as "already locked" here.
+	match lock_file(&fd, lock_type, BlockMode::NonBlocking) {
+		Ok(()) => return Ok(()),
+		Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::PermissionDenied) => {}
+		Err(e) => return Err(e),
+	}
+	on_contended();
+	lock_file(fd, lock_type, BlockMode::Blocking)
+}
+#[cfg(windows)]
+pub fn lock_file_with_notify(handle: impl AsHandle, lock_type: LockType, on_contended: impl FnOnce()) -> Result<()> {
+	// A non-blocking LockFileEx fails with ERROR_LOCK_VIOLATION, not a WouldBlock-ish
+	// code `std` recognizes, so contention has to be detected by raw OS error.
+	match lock_file(&handle, lock_type, BlockMode::NonBlocking) {
+		Ok(()) => return Ok(()),
+		Err(e) if e.raw_os_error() == Some(winapi::shared::winerror::ERROR_LOCK_VIOLATION as i32) => {}
+		Err(e) => return Err(e),
+	}
+	on_contended();
+	lock_file(handle, lock_type, BlockMode::Blocking)
+}
+
+/// An RAII guard over a locked [`File`].
+///
+/// The lock is released automatically when the guard is dropped, so callers
+/// can't forget to unlock, and the lock is released on early return or panic.
+/// Errors from the unlock attempt are ignored, since there's nothing
+/// meaningful to do with them in a `Drop` impl.
+pub struct FileGuard {
+	file: File,
+	path: Option<PathBuf>,
+}
+
+impl FileGuard {
+	fn new(file: File, path: Option<PathBuf>) -> Self {
+		FileGuard { file, path }
+	}
+
+	/// The underlying locked file, for I/O beyond what `Read`/`Write`/`Seek` expose.
+	pub fn file(&self) -> &File {
+		&self.file
+	}
+
+	/// The path the file was opened from, if it was opened via [`FileLock::open`].
+	pub fn path(&self) -> Option<&Path> {
+		self.path.as_deref()
+	}
+}
+
+impl Drop for FileGuard {
+	fn drop(&mut self) {
+		let _ = unlock_file(&self.file);
+	}
+}
+
+impl Read for FileGuard {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		self.file.read(buf)
+	}
+}
+
+impl Write for FileGuard {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		self.file.write(buf)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.file.flush()
+	}
+}
+
+impl Seek for FileGuard {
+	fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+		self.file.seek(pos)
+	}
+}
+
+/// Whether [`FileLock::open`] should block until the lock is available.
+pub enum Wait {
+	Yes,
+	No,
+}
+
+/// Whether [`FileLock::open`] should create the file if it doesn't exist.
+pub enum Create {
+	Yes,
+	No,
+}
+
+/// Whether [`FileLock::open`] should take an exclusive or a shared lock.
+pub enum Exclusive {
+	Yes,
+	No,
+}
+
+/// The recommended, guard-based entry point to this crate.
+///
+/// Use the free functions [`lock_file`]/[`unlock_file`] directly only when
+/// you need to manage the lock's lifetime yourself.
+pub struct FileLock;
+
+impl FileLock {
+	/// Lock `file` and return a [`FileGuard`] that releases the lock on drop.
+	pub fn lock(file: File, lock_type: LockType, block_mode: BlockMode) -> Result<FileGuard> {
+		lock_file(&file, lock_type, block_mode)?;
+		Ok(FileGuard::new(file, None))
+	}
+
+	/// Open `path`, applying the right platform-specific open flags, then lock it.
+	///
+	/// On Unix the file is opened read/write with mode `0o700`. On Windows it's
+	/// opened read/write with `FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE`
+	/// so other handles can still be opened while the lock is held (just not locked
+	/// themselves). This centralizes the open flags so callers don't have to get the
+	/// Windows share mode right themselves.
+	pub fn open(path: &Path, wait: Wait, create: Create, exclusive: Exclusive) -> Result<FileGuard> {
+		let block_mode = match wait {
+			Wait::Yes => BlockMode::Blocking,
+			Wait::No => BlockMode::NonBlocking,
+		};
+		let lock_type = match exclusive {
+			Exclusive::Yes => LockType::Exclusive,
+			Exclusive::No => LockType::Shared,
+		};
+		let create = matches!(create, Create::Yes);
+
+		#[cfg(unix)]
+		let file = {
+			use std::os::unix::fs::OpenOptionsExt;
+			File::options().read(true).write(true).create(create).mode(0o700).open(path)?
+		};
+
+		#[cfg(windows)]
+		let file = {
+			use std::os::windows::fs::OpenOptionsExt;
+			use winapi::um::winnt::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+			File::options()
+				.read(true)
+				.write(true)
+				.create(create)
+				.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+				.open(path)?
+		};
+
+		lock_file(&file, lock_type, block_mode)?;
+		Ok(FileGuard::new(file, Some(path.to_path_buf())))
+	}
 }
 
 #[cfg(test)]
@@ -116,6 +313,70 @@ mod tests {
 		std::fs::remove_file(lockfile_name).unwrap();
 	}
 
+	#[test]
+	fn guard_unlocks_on_drop() {
+		let lockfile_name = "guard_unlocks_on_drop.test.lock";
+		let file = File::create(&lockfile_name).unwrap();
+		{
+			let _guard = FileLock::lock(file, LockType::Exclusive, BlockMode::NonBlocking).unwrap();
+		}
+		// Opened for writing: the `fcntl` backend's exclusive lock needs a writable descriptor.
+		let file = File::options().write(true).open(&lockfile_name).unwrap();
+		lock_file(&file, LockType::Exclusive, BlockMode::NonBlocking).unwrap();
+		unlock_file(&file).unwrap();
+		std::fs::remove_file(lockfile_name).unwrap();
+	}
+
+	#[test]
+	fn open_creates_and_locks() {
+		let lockfile_name = "open_creates_and_locks.test.lock";
+		let guard = FileLock::open(lockfile_name.as_ref(), Wait::No, Create::Yes, Exclusive::Yes).unwrap();
+		drop(guard);
+		std::fs::remove_file(lockfile_name).unwrap();
+	}
+
+	#[test]
+	fn guard_reads_and_writes_through_the_lock() {
+		let lockfile_name = "guard_read_write.test.lock";
+		let mut guard = FileLock::open(lockfile_name.as_ref(), Wait::No, Create::Yes, Exclusive::Yes).unwrap();
+		assert_eq!(guard.path(), Some(Path::new(lockfile_name)));
+
+		guard.write_all(b"hello").unwrap();
+		guard.seek(SeekFrom::Start(0)).unwrap();
+		let mut contents = String::new();
+		guard.read_to_string(&mut contents).unwrap();
+		assert_eq!(contents, "hello");
+
+		drop(guard);
+		std::fs::remove_file(lockfile_name).unwrap();
+	}
+
+	// Locks two independent handles to the same file from within a single process and
+	// expects them to conflict. That only holds for backends with per-handle lock
+	// semantics (Linux's `flock`, Windows's `LockFileEx`) — the `fcntl` fallback used
+	// on other Unix targets owns locks per-process, so same-process handles never
+	// conflict there and this test doesn't apply.
+	#[test]
+	#[cfg(any(windows, target_os = "linux"))]
+	fn lock_with_notify_invokes_callback_on_contention() {
+		let lockfile_name = "lock_with_notify.test.lock";
+		let file_a = File::create(&lockfile_name).unwrap();
+		lock_file(&file_a, LockType::Exclusive, BlockMode::NonBlocking).unwrap();
+
+		std::thread::spawn(move || {
+			std::thread::sleep(std::time::Duration::from_millis(100));
+			unlock_file(&file_a).unwrap();
+		});
+
+		let file_b = File::open(&lockfile_name).unwrap();
+		let mut notified = false;
+		lock_file_with_notify(&file_b, LockType::Exclusive, || notified = true).unwrap();
+		assert!(notified);
+
+		unlock_file(&file_b).unwrap();
+		std::fs::remove_file(lockfile_name).unwrap();
+	}
+
 	#[test]
 	fn lock_works() {
 		let lockfile_name = "lock_works.test.lock";
@@ -137,7 +398,7 @@ mod tests {
 			.expect("failed to spawn the test binary");
 
 		std::thread::sleep(std::time::Duration::from_millis(100));
-		
+
 		assert!(lock_file(&file, LockType::Exclusive, BlockMode::NonBlocking).is_err());
 
 		child.kill().expect("failed to kill test binary");